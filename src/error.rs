@@ -66,6 +66,14 @@ quick_error! {
         UdpHolePunchFailed {
             description("Udp Hole punch failed")
         }
+        /// Post-hole-punch encrypted session handshake failed
+        HandshakeFailed {
+            description("Post-hole-punch encrypted session handshake failed")
+        }
+        /// Incoming frame failed MAC verification and was dropped
+        MacVerificationFailed {
+            description("Incoming frame failed MAC verification and was dropped")
+        }
 
         // =======================================
 
@@ -83,6 +91,10 @@ quick_error! {
         UnregisteredSocket {
             description("Socket is not available")
         }
+        /// Source exceeded its token-bucket allowance and was dropped
+        RateLimited {
+            description("Source exceeded its token-bucket allowance and was dropped")
+        }
         /// Unknown error
         Unknown {
             description("Unknown Error in Nat Traversal")
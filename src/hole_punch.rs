@@ -1,5 +1,4 @@
 use {Interface, NatError, NatMsg, NatState, NatTimer};
-use config::{HOLE_PUNCH_TIMEOUT_SEC, RENDEZVOUS_TIMEOUT_SEC};
 use mio::{Poll, Token};
 use mio::channel::Sender;
 use mio::tcp::TcpStream;
@@ -9,9 +8,10 @@ use std::any::Any;
 use std::cell::RefCell;
 use std::fmt::{self, Debug, Formatter};
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::{Rc, Weak};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use session::{self, EncryptedConnection};
 use tcp::TcpHolePunchMediator;
 use udp::UdpHolePunchMediator;
 
@@ -46,8 +46,48 @@ impl Default for HolePunchInfo {
     }
 }
 
+/// Same shape as `HolePunchInfo`, but the socket is paired with the
+/// `EncryptedConnection` negotiated for it. Returned instead of
+/// `HolePunchInfo` only when the caller opts in by calling
+/// `Handle::fire_hole_punch_secure`, so existing plaintext callers of
+/// `fire_hole_punch` see no change; no config setting selects it. `udp` is
+/// never filled in: the secure path drops a punched udp socket.
+#[derive(Debug)]
+pub struct SecureHolePunchInfo {
+    pub tcp: Option<(TcpStream, Token, EncryptedConnection)>,
+    pub udp: Option<(UdpSocket, Token, EncryptedConnection)>,
+}
+
+pub type SecureHolePunchFinish = Box<FnMut(&mut Interface, &Poll, ::Res<SecureHolePunchInfo>) +
+                                     Send +
+                                     'static>;
+
 const TIMER_ID: u8 = 0;
 
+/// Remaining time until `deadline`, clamped to zero - feeding this straight
+/// into `set_timeout` makes an already-expired deadline fire immediately
+/// instead of silently granting a fresh window.
+pub(crate) fn duration_until(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+/// An orderable stand-in for `SocketAddr`, which implements neither
+/// `PartialOrd` nor `Ord`: maps the IP (v4 addresses via their
+/// IPv4-mapped-in-IPv6 form, so both families compare on the same 16 bytes)
+/// and port into a tuple that does.
+fn addr_key(addr: SocketAddr) -> ([u8; 16], u16) {
+    let octets = match addr.ip() {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    };
+    (octets, addr.port())
+}
+
 enum State {
     None,
     Rendezvous {
@@ -83,10 +123,9 @@ pub struct HolePunchMediator {
 }
 
 impl HolePunchMediator {
-    pub fn start(ifc: &mut Interface, poll: &Poll, f: GetInfo) -> ::Res<()> {
+    pub fn start(ifc: &mut Interface, poll: &Poll, deadline: Instant, f: GetInfo) -> ::Res<()> {
         let token = ifc.new_token();
-        let dur = ifc.config().rendezvous_timeout_sec.unwrap_or(RENDEZVOUS_TIMEOUT_SEC);
-        let timeout = ifc.set_timeout(Duration::from_secs(dur), NatTimer::new(token, TIMER_ID))?;
+        let timeout = ifc.set_timeout(duration_until(deadline), NatTimer::new(token, TIMER_ID))?;
 
         let mediator = Rc::new(RefCell::new(HolePunchMediator {
             token: token,
@@ -103,7 +142,7 @@ impl HolePunchMediator {
             mediator.borrow_mut().handle_udp_rendezvous(ifc, poll, res);
         };
 
-        let udp_child = match UdpHolePunchMediator::start(ifc, poll, Box::new(handler)) {
+        let udp_child = match UdpHolePunchMediator::start(ifc, poll, deadline, Box::new(handler)) {
             Ok(child) => Some(child),
             Err(e) => {
                 debug!("Udp Hole Punch Mediator failed to initialise: {:?}", e);
@@ -111,7 +150,19 @@ impl HolePunchMediator {
             }
         };
 
-        let tcp_child = None; // TODO Put TCP logic here
+        let weak = Rc::downgrade(&mediator);
+        let tcp_handler = move |ifc: &mut Interface, poll: &Poll, res| if let Some(mediator) =
+            weak.upgrade() {
+            mediator.borrow_mut().handle_tcp_rendezvous(ifc, poll, res);
+        };
+
+        let tcp_child = match TcpHolePunchMediator::start(ifc, poll, deadline, Box::new(tcp_handler)) {
+            Ok(child) => Some(child),
+            Err(e) => {
+                debug!("Tcp Hole Punch Mediator failed to initialise: {:?}", e);
+                None
+            }
+        };
 
         if udp_child.is_none() && tcp_child.is_none() {
             Err(NatError::RendezvousFailed)
@@ -151,6 +202,12 @@ impl HolePunchMediator {
                 } else {
                     self.udp_child = None;
                 }
+                // Complete only once the sibling protocol has definitively
+                // reported too (succeeded, reflected in `info.tcp`, or
+                // failed, reflected in `tcp_child` being gone) - otherwise a
+                // udp-only `RendezvousInfo` would be handed back and the tcp
+                // addresses silently lost even though tcp was still in
+                // flight and might yet succeed.
                 if self.tcp_child.is_none() || !info.tcp.is_empty() {
                     if self.udp_child.is_none() && self.tcp_child.is_none() {
                         f(ifc, poll, Err(NatError::RendezvousFailed));
@@ -191,9 +248,108 @@ impl HolePunchMediator {
         }
     }
 
+    /// Runs the `session::handshake_over_tcp` exchange on the tcp socket in
+    /// `info`, dropping (rather than failing outright) the socket if its
+    /// handshake does not succeed - mirroring how a failed UDP or TCP child
+    /// is simply left out of the final `HolePunchInfo`. The udp socket, if
+    /// any, is always dropped (see below). `deadline` bounds how long the
+    /// handshake is allowed to block the event-loop thread waiting on a
+    /// peer that never replies.
+    fn secure(info: HolePunchInfo, deadline: Instant) -> ::Res<SecureHolePunchInfo> {
+        let mut secure_info = SecureHolePunchInfo { tcp: None, udp: None };
+
+        if let Some((stream, token)) = info.tcp {
+            let we_initiate = match (stream.local_addr(), stream.peer_addr()) {
+                (Ok(l), Ok(p)) => addr_key(l) < addr_key(p),
+                _ => false,
+            };
+            match session::handshake_over_tcp(stream, we_initiate, deadline) {
+                Ok((stream, conn)) => secure_info.tcp = Some((stream, token, conn)),
+                Err(e) => debug!("Secure handshake over tcp failed, dropping socket: {:?}", e),
+            }
+        }
+
+        // `EncryptedConnection` authenticates with a single running MAC
+        // updated in strict send order, which a lost or reordered UDP
+        // datagram would desync permanently - so, for now, the encrypted
+        // session layer only ever runs over TCP. A punched UDP socket is
+        // simply left out of `SecureHolePunchInfo`; it is still available
+        // unsecured via the plain `fire_hole_punch`/`HolePunchInfo` path.
+        if info.udp.is_some() {
+            debug!("Secure hole punch has no udp support (a running MAC cannot survive packet \
+                    loss/reordering), dropping the punched udp socket");
+        }
+
+        if secure_info.tcp.is_none() && secure_info.udp.is_none() {
+            Err(NatError::HandshakeFailed)
+        } else {
+            Ok(secure_info)
+        }
+    }
+
+    fn handle_tcp_rendezvous(&mut self,
+                             ifc: &mut Interface,
+                             poll: &Poll,
+                             res: ::Res<Vec<SocketAddr>>) {
+        let r = match self.state {
+            State::Rendezvous { ref mut info, ref mut f, ref timeout } => {
+                if let Ok(ext_addrs) = res {
+                    // We assume that tcp_child does not return an empty list here - rather it
+                    // should error out on such case (i.e. call us with an error)
+                    info.tcp = ext_addrs;
+                } else {
+                    self.tcp_child = None;
+                }
+                // Complete only once the sibling protocol has definitively
+                // reported too (succeeded, reflected in `info.udp`, or
+                // failed, reflected in `udp_child` being gone) - otherwise a
+                // tcp-only `RendezvousInfo` would be handed back and the udp
+                // addresses silently lost even though udp was still in
+                // flight and might yet succeed.
+                if self.udp_child.is_none() || !info.udp.is_empty() {
+                    if self.udp_child.is_none() && self.tcp_child.is_none() {
+                        f(ifc, poll, Err(NatError::RendezvousFailed));
+                        Err(NatError::RendezvousFailed)
+                    } else {
+                        let _ = ifc.cancel_timeout(timeout);
+                        let info = mem::replace(info, Default::default());
+                        let handle = Handle {
+                            token: self.token,
+                            tx: ifc.sender().clone(),
+                        };
+                        f(ifc, poll, Ok((handle, info)));
+                        Ok(true)
+                    }
+                } else {
+                    Ok(false)
+                }
+            }
+            ref x => {
+                warn!("Logic Error in state book-keeping - Pls report this as a bug. Expected \
+                       state: State::Rendezvous ;; Found: {:?}",
+                      x);
+                Err(NatError::InvalidState)
+            }
+        };
+
+        match r {
+            Ok(true) => self.state = State::ReadyToHolePunch,
+            Ok(false) => (),
+            Err(e @ NatError::RendezvousFailed) => {
+                // This is reached only if children is empty. So no chance of borrow violation for
+                // children in terminate()
+                debug!("Terminating due to: {:?}", e);
+                self.terminate(ifc, poll);
+            }
+            // Don't call terminate as that can lead to child being borrowed twice
+            Err(e) => debug!("Ignoring error in handle hole-punch: {:?}", e),
+        }
+    }
+
     fn punch_hole(&mut self,
                   ifc: &mut Interface,
                   poll: &Poll,
+                  deadline: Instant,
                   peers: RendezvousInfo,
                   mut f: HolePunchFinsih) {
         match self.state {
@@ -204,8 +360,7 @@ impl HolePunchMediator {
             }
         };
 
-        let dur = ifc.config().hole_punch_timeout_sec.unwrap_or(HOLE_PUNCH_TIMEOUT_SEC);
-        let timeout = match ifc.set_timeout(Duration::from_secs(dur),
+        let timeout = match ifc.set_timeout(duration_until(deadline),
                                             NatTimer::new(self.token, TIMER_ID)) {
             Ok(t) => t,
             Err(e) => {
@@ -221,12 +376,25 @@ impl HolePunchMediator {
                 mediator.borrow_mut().handle_udp_hole_punch(ifc, poll, res);
             };
             if let Err(e) = udp_child.borrow_mut()
-                .punch_hole(ifc, poll, peers.udp, Box::new(handler)) {
+                .punch_hole(ifc, poll, deadline, peers.udp, Box::new(handler)) {
                 debug!("Udp punch hole failed to start: {:?}", e);
                 self.udp_child = None;
             }
         }
 
+        if let Some(tcp_child) = self.tcp_child.as_ref().cloned() {
+            let weak = self.self_weak.clone();
+            let handler = move |ifc: &mut Interface, poll: &Poll, res| if let Some(mediator) =
+                weak.upgrade() {
+                mediator.borrow_mut().handle_tcp_hole_punch(ifc, poll, res);
+            };
+            if let Err(e) = tcp_child.borrow_mut()
+                .punch_hole(ifc, poll, deadline, peers.tcp, Box::new(handler)) {
+                debug!("Tcp punch hole failed to start: {:?}", e);
+                self.tcp_child = None;
+            }
+        }
+
         if self.udp_child.is_none() && self.tcp_child.is_none() {
             debug!("Failure: Not even one valid child even managed to start hole punching");
             self.terminate(ifc, poll);
@@ -284,6 +452,51 @@ impl HolePunchMediator {
             Err(e) => debug!("Ignoring error in handle udp-hole-punch: {:?}", e),
         }
     }
+
+    fn handle_tcp_hole_punch(&mut self,
+                             ifc: &mut Interface,
+                             poll: &Poll,
+                             res: ::Res<(TcpStream, Token)>) {
+        let r = match self.state {
+            State::HolePunching { ref mut info, ref mut f, .. } => {
+                self.tcp_child = None;
+                if let Ok(sock) = res {
+                    info.tcp = Some(sock);
+                }
+                if self.tcp_child.is_none() && self.udp_child.is_none() {
+                    if info.tcp.is_none() && info.udp.is_none() {
+                        f(ifc, poll, Err(NatError::HolePunchFailed));
+                        Err(NatError::HolePunchFailed)
+                    } else {
+                        let info = mem::replace(info, Default::default());
+                        f(ifc, poll, Ok(info));
+                        Ok(true)
+                    }
+                } else {
+                    Ok(false)
+                }
+            }
+            ref x => {
+                warn!("Logic Error in state book-keeping - Pls report this as a bug. Expected \
+                       state: State::HolePunching ;; Found: {:?}",
+                      x);
+                Err(NatError::InvalidState)
+            }
+        };
+
+        match r {
+            Ok(true) => self.terminate(ifc, poll),
+            Ok(false) => (),
+            Err(e @ NatError::HolePunchFailed) => {
+                // This is reached only if children is empty. So no chance of borrow violation for
+                // children in terminate()
+                debug!("Terminating due to: {:?}", e);
+                self.terminate(ifc, poll);
+            }
+            // Don't call terminate as that can lead to child being borrowed twice
+            Err(e) => debug!("Ignoring error in handle tcp-hole-punch: {:?}", e),
+        }
+    }
 }
 
 impl NatState for HolePunchMediator {
@@ -298,9 +511,9 @@ impl NatState for HolePunchMediator {
                     let r = udp_child.borrow_mut().rendezvous_timeout(ifc, poll);
                     self.handle_udp_rendezvous(ifc, poll, r);
                 }
-                if let Some(_tcp_child) = self.tcp_child.as_ref().cloned() {
-                    // let r = tcp_child.borrow_mut().rendezvous_timeout(ifc, poll);
-                    // self.handle_tcp_rendezvous(ifc, poll, r);
+                if let Some(tcp_child) = self.tcp_child.as_ref().cloned() {
+                    let r = tcp_child.borrow_mut().rendezvous_timeout(ifc, poll);
+                    self.handle_tcp_rendezvous(ifc, poll, r);
                 }
 
                 false
@@ -356,10 +569,10 @@ pub struct Handle {
 }
 
 impl Handle {
-    pub fn fire_hole_punch(self, peers: RendezvousInfo, f: HolePunchFinsih) {
+    pub fn fire_hole_punch(self, deadline: Instant, peers: RendezvousInfo, f: HolePunchFinsih) {
         let token = self.token;
         if let Err(e) = self.tx.send(NatMsg::new(move |ifc, poll| {
-            Handle::start_hole_punch(ifc, poll, token, peers, f)
+            Handle::start_hole_punch(ifc, poll, token, deadline, peers, f)
         })) {
             debug!("Could not fire hole punch request: {:?}", e);
         } else {
@@ -370,6 +583,7 @@ impl Handle {
     pub fn start_hole_punch(ifc: &mut Interface,
                             poll: &Poll,
                             hole_punch_mediator: Token,
+                            deadline: Instant,
                             peers: RendezvousInfo,
                             mut f: HolePunchFinsih) {
         if let Some(nat_state) = ifc.state(hole_punch_mediator) {
@@ -381,11 +595,35 @@ impl Handle {
                     return f(ifc, poll, Err(NatError::InvalidState));
                 }
             };
-            mediator.punch_hole(ifc, poll, peers, f);
+            mediator.punch_hole(ifc, poll, deadline, peers, f);
 
         }
     }
 
+    /// Like `fire_hole_punch`, but additionally runs the post-hole-punch
+    /// ECIES-style handshake on the tcp socket, if one comes up, and hands
+    /// back a `SecureHolePunchInfo` instead of a bare `HolePunchInfo`. A
+    /// socket whose handshake or MAC setup fails is dropped from the result
+    /// the same way a failed punch is; a punched udp socket is always
+    /// dropped.
+    pub fn fire_hole_punch_secure(self,
+                                   deadline: Instant,
+                                   peers: RendezvousInfo,
+                                   mut f: SecureHolePunchFinish) {
+        let token = self.token;
+        let plain_f: HolePunchFinsih = Box::new(move |ifc, poll, res| {
+            let secured = res.and_then(|info| HolePunchMediator::secure(info, deadline));
+            f(ifc, poll, secured);
+        });
+        if let Err(e) = self.tx.send(NatMsg::new(move |ifc, poll| {
+            Handle::start_hole_punch(ifc, poll, token, deadline, peers, plain_f)
+        })) {
+            debug!("Could not fire secure hole punch request: {:?}", e);
+        } else {
+            mem::forget(self);
+        }
+    }
+
     pub fn mediator_token(self) -> Token {
         let token = self.token;
         mem::forget(self);
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Per-source token bucket, the same shape wireguard-rs uses to bound the
+/// amount of handshake work a single peer can trigger. Each bucket refills
+/// lazily (no background ticker) and is only touched when that source
+/// actually sends a packet.
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Keyed rate limiter for the UDP rendezvous server: one bucket per source
+/// `IpAddr`, so a single noisy or spoofed peer can only ever exhaust its own
+/// allowance rather than the server's.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_rate: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: f64, refill_rate: f64) -> Self {
+        RateLimiter {
+            capacity: capacity,
+            refill_rate: refill_rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Refills `addr`'s bucket for the elapsed time and, if at least one
+    /// token is available, consumes it and returns `true`. Returns `false`
+    /// if the source should be dropped - the bucket is still refilled and
+    /// its `last_refill` updated either way, only the token consumption is
+    /// skipped.
+    pub fn allow(&mut self, addr: IpAddr, now: Instant) -> bool {
+        let capacity = self.capacity;
+        let refill_rate = self.refill_rate;
+        let bucket = self.buckets.entry(addr).or_insert_with(|| {
+            Bucket {
+                tokens: capacity,
+                last_refill: now,
+            }
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = (bucket.tokens + elapsed_secs * refill_rate).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drops buckets that have not been touched since `now - idle_after`,
+    /// so a rendezvous server running for a long time does not accumulate
+    /// one entry per distinct source IP it has ever seen.
+    pub fn gc(&mut self, now: Instant, idle_after: Duration) {
+        self.buckets.retain(|_, bucket| now.duration_since(bucket.last_refill) < idle_after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, n))
+    }
+
+    #[test]
+    fn consume_drains_and_refill_tops_up() {
+        let mut limiter = RateLimiter::new(2.0, 1.0);
+        let now = Instant::now();
+
+        assert!(limiter.allow(addr(1), now));
+        assert!(limiter.allow(addr(1), now));
+        assert!(!limiter.allow(addr(1), now));
+
+        let later = now + Duration::from_millis(1500);
+        assert!(limiter.allow(addr(1), later));
+        assert!(!limiter.allow(addr(1), later));
+    }
+
+    #[test]
+    fn refill_is_capped_at_capacity() {
+        let mut limiter = RateLimiter::new(2.0, 100.0);
+        let now = Instant::now();
+
+        assert!(limiter.allow(addr(1), now));
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.allow(addr(1), later));
+        assert!(limiter.allow(addr(1), later));
+        assert!(!limiter.allow(addr(1), later));
+    }
+
+    #[test]
+    fn buckets_are_independent_per_source() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        let now = Instant::now();
+
+        assert!(limiter.allow(addr(1), now));
+        assert!(!limiter.allow(addr(1), now));
+        assert!(limiter.allow(addr(2), now));
+    }
+
+    #[test]
+    fn gc_drops_only_idle_buckets() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        let now = Instant::now();
+
+        assert!(limiter.allow(addr(1), now));
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.allow(addr(2), later));
+
+        limiter.gc(later, Duration::from_secs(30));
+
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(limiter.buckets.contains_key(&addr(2)));
+    }
+}
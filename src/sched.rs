@@ -0,0 +1,306 @@
+use {Handle, HolePunchFinsih, HolePunchMediator, GetInfo, Interface, NatError, RendezvousInfo};
+use fringe::{Generator, OsStack};
+use fringe::generator::Yielder;
+use mio::Poll;
+use std::time::Instant;
+
+/// What a coroutine is blocked on. `action`, if set, is run exactly once by
+/// `Scheduler::turn` - the first time it sees this request - against the
+/// `Interface`/`Poll` of the mio loop driving the scheduler; this is how a
+/// coroutine gets at them at all, since its body only ever runs on the
+/// generator's own stack, never on the event-loop thread's call stack.
+/// `event` is then polled by the executor on every subsequent turn;
+/// `timeout`, if set, fires the coroutine back up with
+/// `WaitResult::TimedOut` even if `event` never becomes true.
+pub struct WaitRequest {
+    pub action: Option<Box<FnOnce(&mut Interface, &Poll)>>,
+    pub event: Option<Box<Fn() -> bool>>,
+    pub timeout: Option<Instant>,
+}
+
+/// Outcome handed back to a coroutine when it is resumed, mirroring the
+/// three ways ARTIQ's `sched.rs` can return control to blocking user code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitResult {
+    Completed,
+    TimedOut,
+    Interrupted,
+}
+
+/// The blocking handle a coroutine uses to call back into the async API.
+/// Every method here yields a `WaitRequest` to the executor and only
+/// returns once the executor resumes the coroutine with the outcome -
+/// from the coroutine's point of view these are ordinary, straight-line
+/// blocking calls.
+pub struct Io<'a> {
+    yielder: &'a Yielder<WaitResult, WaitRequest>,
+}
+
+impl<'a> Io<'a> {
+    fn wait(&self, req: WaitRequest) -> ::Res<()> {
+        match self.yielder.suspend(req) {
+            WaitResult::Completed => Ok(()),
+            WaitResult::TimedOut => Err(NatError::HolePunchFailed),
+            WaitResult::Interrupted => Err(NatError::Unknown),
+        }
+    }
+
+    /// Blocking equivalent of `HolePunchMediator::start`. Unlike the old
+    /// signature this takes no `Interface`/`Poll` - the coroutine body has
+    /// no way to produce those itself - instead the actual `start()` call is
+    /// deferred into a `WaitRequest::action` that `Scheduler::turn` runs
+    /// against its own `Interface`/`Poll` the next time it resumes us.
+    pub fn get_rendezvous_info(&self, deadline: Instant) -> ::Res<(Handle, RendezvousInfo)> {
+        let slot = ::std::rc::Rc::new(::std::cell::RefCell::new(None));
+        let slot_cb = slot.clone();
+        let f: GetInfo = Box::new(move |_ifc, _poll, res| {
+            *slot_cb.borrow_mut() = Some(res);
+        });
+
+        let slot_action = slot.clone();
+        let action: Box<FnOnce(&mut Interface, &Poll)> = Box::new(move |ifc, poll| {
+            if let Err(e) = HolePunchMediator::start(ifc, poll, deadline, f) {
+                // `start` only ever calls `f` once it has registered itself; on an
+                // immediate failure it never does, so we have to fill the slot
+                // ourselves or the event predicate below would wait forever.
+                *slot_action.borrow_mut() = Some(Err(e));
+            }
+        });
+
+        self.wait(WaitRequest {
+                action: Some(action),
+                event: Some(Box::new({
+                    let slot = slot.clone();
+                    move || slot.borrow().is_some()
+                })),
+                timeout: None,
+            })?;
+
+        slot.borrow_mut().take().unwrap_or(Err(NatError::RendezvousFailed))
+    }
+
+    /// Blocking equivalent of `Handle::fire_hole_punch`.
+    pub fn fire_hole_punch(&self,
+                            handle: Handle,
+                            deadline: Instant,
+                            peers: RendezvousInfo)
+                            -> ::Res<::HolePunchInfo> {
+        // Unlike `get_rendezvous_info`'s `GetInfo`, `HolePunchFinsih` carries
+        // a `Send` bound - the mediator posts it across to a worker thread's
+        // own event loop rather than calling it in place - so the slot it
+        // closes over has to be `Send` too, which rules out `Rc<RefCell<..>>`.
+        let slot = ::std::sync::Arc::new(::std::sync::Mutex::new(None));
+        let slot_cb = slot.clone();
+        let f: HolePunchFinsih = Box::new(move |_ifc, _poll, res| {
+            *slot_cb.lock().unwrap() = Some(res);
+        });
+        // `Handle::fire_hole_punch` only ever posts `f` onto the mediator's
+        // own event-loop channel - it never touches `Interface`/`Poll`
+        // itself - so, unlike `get_rendezvous_info`, no deferred action is
+        // needed here; the call is safe to make right from the coroutine.
+        handle.fire_hole_punch(deadline, peers, f);
+
+        self.wait(WaitRequest {
+                action: None,
+                event: Some(Box::new({
+                    let slot = slot.clone();
+                    move || slot.lock().unwrap().is_some()
+                })),
+                timeout: None,
+            })?;
+
+        slot.lock().unwrap().take().unwrap_or(Err(NatError::HolePunchFailed))
+    }
+}
+
+/// A single blocking-style task, backed by a stackful coroutine (`fringe`'s
+/// generator) rather than a plain callback. The coroutine body runs with an
+/// `Io` handle and yields a `WaitRequest` every time it would otherwise
+/// block; `Scheduler` resumes it once that request is satisfied.
+pub struct Task {
+    generator: Generator<WaitResult, WaitRequest, OsStack>,
+    pending: Option<WaitRequest>,
+}
+
+impl Task {
+    pub fn spawn<F>(stack: OsStack, body: F) -> Task
+        where F: FnOnce(Io) + Send + 'static
+    {
+        let mut generator = Generator::new(stack, move |yielder, _| {
+            body(Io { yielder: yielder });
+        });
+        let pending = generator.resume(WaitResult::Completed);
+        Task {
+            generator: generator,
+            pending: pending,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.pending.is_none()
+    }
+}
+
+/// Drives a batch of `Task`s to completion against the existing mio `Poll`.
+/// Each turn of `poll()` checks every still-running task's `WaitRequest`:
+/// an elapsed `timeout` or a satisfied `event` predicate resumes that task,
+/// everything else is left parked until the next turn.
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler { tasks: Vec::new() }
+    }
+
+    pub fn spawn<F>(&mut self, stack: OsStack, body: F)
+        where F: FnOnce(Io) + Send + 'static
+    {
+        self.tasks.push(Task::spawn(stack, body));
+    }
+
+    /// Runs one scheduling turn, resuming every task whose wait condition
+    /// is now satisfied. Call this from the same loop that drives the
+    /// existing mio `Poll` (e.g. once per `Poll::poll` return), passing that
+    /// loop's own `Interface`/`Poll` through - this is the only place a
+    /// coroutine's deferred `WaitRequest::action` ever actually runs.
+    pub fn turn(&mut self, ifc: &mut Interface, poll: &Poll) {
+        let now = Instant::now();
+        for task in &mut self.tasks {
+            if task.is_done() {
+                continue;
+            }
+            if let Some(action) = task.pending.as_mut().and_then(|req| req.action.take()) {
+                action(ifc, poll);
+            }
+            let result = match task.pending.as_ref() {
+                Some(req) => {
+                    let timed_out = req.timeout.map(|t| now >= t).unwrap_or(false);
+                    let fired = req.event.as_ref().map(|e| e()).unwrap_or(false);
+                    if timed_out {
+                        Some(WaitResult::TimedOut)
+                    } else if fired {
+                        Some(WaitResult::Completed)
+                    } else {
+                        None
+                    }
+                }
+                None => None,
+            };
+
+            if let Some(result) = result {
+                task.pending = task.generator.resume(result);
+            }
+        }
+        self.tasks.retain(|t| !t.is_done());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const STACK_SIZE: usize = 64 * 1024;
+
+    // These exercise the coroutine/Io::wait plumbing directly, driving the
+    // generator with `resume` by hand exactly as `Scheduler::turn` would -
+    // none of it touches `Interface`/`Poll`, so no event loop is needed.
+
+    #[test]
+    fn task_suspends_until_resumed_and_then_completes() {
+        let outcome = Rc::new(RefCell::new(None));
+        let outcome_body = outcome.clone();
+
+        let stack = OsStack::new(STACK_SIZE).unwrap();
+        let mut task = Task::spawn(stack, move |io: Io| {
+            let result = io.wait(WaitRequest {
+                action: None,
+                event: Some(Box::new(|| true)),
+                timeout: None,
+            });
+            *outcome_body.borrow_mut() = Some(result);
+        });
+
+        // The body suspended at `wait` before ever writing to `outcome`.
+        assert!(!task.is_done());
+        assert!(outcome.borrow().is_none());
+
+        task.pending = task.generator.resume(WaitResult::Completed);
+
+        assert!(task.is_done());
+        match outcome.borrow_mut().take() {
+            Some(Ok(())) => (),
+            other => panic!("expected Ok(()), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn timed_out_wait_becomes_hole_punch_failed() {
+        let outcome = Rc::new(RefCell::new(None));
+        let outcome_body = outcome.clone();
+
+        let stack = OsStack::new(STACK_SIZE).unwrap();
+        let mut task = Task::spawn(stack, move |io: Io| {
+            let result = io.wait(WaitRequest {
+                action: None,
+                event: None,
+                timeout: None,
+            });
+            *outcome_body.borrow_mut() = Some(result);
+        });
+
+        task.pending = task.generator.resume(WaitResult::TimedOut);
+
+        assert!(task.is_done());
+        match outcome.borrow_mut().take() {
+            Some(Err(NatError::HolePunchFailed)) => (),
+            other => panic!("expected HolePunchFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interrupted_wait_becomes_unknown_error() {
+        let outcome = Rc::new(RefCell::new(None));
+        let outcome_body = outcome.clone();
+
+        let stack = OsStack::new(STACK_SIZE).unwrap();
+        let mut task = Task::spawn(stack, move |io: Io| {
+            let result = io.wait(WaitRequest {
+                action: None,
+                event: None,
+                timeout: None,
+            });
+            *outcome_body.borrow_mut() = Some(result);
+        });
+
+        task.pending = task.generator.resume(WaitResult::Interrupted);
+
+        assert!(task.is_done());
+        match outcome.borrow_mut().take() {
+            Some(Err(NatError::Unknown)) => (),
+            other => panic!("expected Unknown, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn spawn_adds_a_not_yet_done_task_to_the_scheduler() {
+        // `Scheduler::turn` itself needs a real `Interface`/`Poll` to drive
+        // `WaitRequest::action` and isn't exercised here; this only covers
+        // the part of the scheduler that has no event-loop dependency.
+        let mut scheduler = Scheduler::new();
+        scheduler.spawn(OsStack::new(STACK_SIZE).unwrap(), |io: Io| {
+            let _ = io.wait(WaitRequest {
+                action: None,
+                event: Some(Box::new(|| false)),
+                timeout: None,
+            });
+        });
+
+        assert_eq!(scheduler.tasks.len(), 1);
+        assert!(!scheduler.tasks[0].is_done());
+    }
+}
@@ -0,0 +1,335 @@
+use NatError;
+use bincode::{deserialize, serialize, Infinite};
+use mio::tcp::TcpStream;
+use rust_sodium::crypto::box_;
+use rust_sodium::crypto::stream::xsalsa20;
+use rust_sodium::randombytes::randombytes_into;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::net::TcpStream as StdTcpStream;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::time::{Duration, Instant};
+use tiny_keccak::Keccak;
+
+const NONCE_LEN: usize = 32;
+const MAC_LEN: usize = 16;
+
+/// `auth` message sent by the initiator of the post-hole-punch handshake.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthMessage {
+    pub public_key: box_::PublicKey,
+    pub nonce: [u8; NONCE_LEN],
+}
+
+/// `ack` message sent in reply, completing the ECDH exchange.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AckMessage {
+    pub public_key: box_::PublicKey,
+    pub nonce: [u8; NONCE_LEN],
+}
+
+/// A confidential, integrity-protected channel established over a punched
+/// socket, modelled on OpenEthereum's `EncryptedConnection`. Key material is
+/// derived once from the ECDH shared secret and the two handshake nonces;
+/// after that every frame is encrypted with the raw XSalsa20 keystream (no
+/// baked-in Poly1305 tag - `rust_sodium::crypto::secretbox` bundles one and
+/// would leave every frame carrying two MACs) and authenticated with a
+/// single running Keccak-256 MAC so that re-ordered or tampered frames are
+/// rejected before they reach the caller.
+///
+/// Only ever constructed over TCP: the running MAC is seeded once and
+/// updated frame-by-frame in send/receive order, which a lost or reordered
+/// UDP datagram would desync permanently. `handshake_over_tcp` is the only
+/// handshake provided.
+pub struct EncryptedConnection {
+    peer_addr: SocketAddr,
+    payload_key: xsalsa20::Key,
+    egress_mac: Keccak,
+    ingress_mac: Keccak,
+}
+
+impl Debug for EncryptedConnection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "EncryptedConnection {{ peer_addr: {:?}, .. }}", self.peer_addr)
+    }
+}
+
+impl EncryptedConnection {
+    /// Completes the handshake and derives the session keys. `we_are_initiator`
+    /// decides the order in which the two nonces are hashed (initiator's
+    /// first), so both ends derive the same key material. The MAC seeds do
+    /// not depend on it: egress is seeded with the peer's nonce and ingress
+    /// with our own, which gives the two ends swapped MAC seeds (otherwise
+    /// every frame would fail to verify).
+    pub fn new(peer_addr: SocketAddr,
+               our_secret: &box_::SecretKey,
+               our_nonce: &[u8; NONCE_LEN],
+               their_public: &box_::PublicKey,
+               their_nonce: &[u8; NONCE_LEN],
+               we_are_initiator: bool)
+               -> ::Res<Self> {
+        let shared = box_::precompute(their_public, our_secret);
+
+        let mut nonce_hash = Keccak::new_keccak256();
+        let (first, second) = if we_are_initiator {
+            (&our_nonce[..], &their_nonce[..])
+        } else {
+            (&their_nonce[..], &our_nonce[..])
+        };
+        nonce_hash.update(first);
+        nonce_hash.update(second);
+        let mut nonce_digest = [0u8; 32];
+        nonce_hash.finalize(&mut nonce_digest);
+
+        let mut key_hash = Keccak::new_keccak256();
+        key_hash.update(&shared.0);
+        key_hash.update(&nonce_digest);
+        let mut key_material = [0u8; 32];
+        key_hash.finalize(&mut key_material);
+
+        let mut mac_hash = Keccak::new_keccak256();
+        mac_hash.update(&key_material);
+        let mut mac_key = [0u8; 32];
+        mac_hash.finalize(&mut mac_key);
+
+        let payload_key = xsalsa20::Key(key_material);
+
+        // Egress is always keyed with the *peer's* nonce and ingress with our
+        // own, regardless of who initiated: that is what makes our egress
+        // seed equal the peer's ingress seed (and vice versa) so the two
+        // sides' running MACs stay in lock-step frame for frame.
+        let egress_seed = xor32(&mac_key, their_nonce);
+        let ingress_seed = xor32(&mac_key, our_nonce);
+
+        let mut egress_mac = Keccak::new_keccak256();
+        egress_mac.update(&egress_seed);
+        let mut ingress_mac = Keccak::new_keccak256();
+        ingress_mac.update(&ingress_seed);
+
+        Ok(EncryptedConnection {
+            peer_addr: peer_addr,
+            payload_key: payload_key,
+            egress_mac: egress_mac,
+            ingress_mac: ingress_mac,
+        })
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Encrypts `plain` and appends a 16-byte MAC tag computed over the
+    /// ciphertext, feeding it into the running egress MAC.
+    pub fn encrypt_frame(&mut self, plain: &[u8]) -> Vec<u8> {
+        let nonce = xsalsa20::gen_nonce();
+        let mut ciphertext = xsalsa20::stream_xor(plain, &nonce, &self.payload_key);
+
+        self.egress_mac.update(&ciphertext);
+        let mut tag = [0u8; MAC_LEN];
+        self.egress_mac.clone().finalize(&mut tag);
+
+        let mut frame = nonce.0.to_vec();
+        frame.append(&mut ciphertext);
+        frame.extend_from_slice(&tag);
+        frame
+    }
+
+    /// Verifies the trailing MAC tag against the running ingress MAC and, on
+    /// success, decrypts the frame. Frames that fail verification are
+    /// rejected with `NatError::MacVerificationFailed` and never reach the
+    /// caller.
+    pub fn decrypt_frame(&mut self, frame: &[u8]) -> ::Res<Vec<u8>> {
+        if frame.len() < xsalsa20::NONCEBYTES + MAC_LEN {
+            return Err(NatError::MacVerificationFailed);
+        }
+        let (body, tag) = frame.split_at(frame.len() - MAC_LEN);
+        let (nonce_bytes, ciphertext) = body.split_at(xsalsa20::NONCEBYTES);
+
+        self.ingress_mac.update(ciphertext);
+        let mut expected_tag = [0u8; MAC_LEN];
+        self.ingress_mac.clone().finalize(&mut expected_tag);
+        if expected_tag != *tag {
+            return Err(NatError::MacVerificationFailed);
+        }
+
+        let nonce = xsalsa20::Nonce::from_slice(nonce_bytes)
+            .ok_or(NatError::MacVerificationFailed)?;
+        Ok(xsalsa20::stream_xor(ciphertext, &nonce, &self.payload_key))
+    }
+}
+
+/// Runs the auth/ack exchange over an already-connected `TcpStream` and
+/// returns the resulting `EncryptedConnection` together with the socket.
+///
+/// `stream` is a non-blocking mio socket, but the auth/ack exchange below
+/// does plain blocking `read`/`write` calls, so this takes the socket by
+/// value, flips the underlying fd to blocking (with a read timeout bounded
+/// by `deadline`, so a peer that never replies cannot hang this thread
+/// forever) for the few round-trips the handshake needs, then flips it back
+/// to non-blocking before handing it back - the registration with `Poll`
+/// made before calling this is unaffected, only the fd's blocking mode
+/// changes and only for this call's duration. `we_are_initiator` decides who
+/// sends the `auth` message first; the other side replies with `ack`.
+#[cfg(unix)]
+pub fn handshake_over_tcp(stream: TcpStream,
+                           we_are_initiator: bool,
+                           deadline: Instant)
+                           -> ::Res<(TcpStream, EncryptedConnection)> {
+    let dur = ::hole_punch::duration_until(deadline);
+    if dur == Duration::from_secs(0) {
+        return Err(NatError::HandshakeFailed);
+    }
+
+    let mut std_stream = unsafe { StdTcpStream::from_raw_fd(stream.into_raw_fd()) };
+    std_stream.set_nonblocking(false)?;
+    std_stream.set_read_timeout(Some(dur))?;
+
+    let result = run_tcp_handshake(&mut std_stream, we_are_initiator);
+
+    std_stream.set_read_timeout(None)?;
+    std_stream.set_nonblocking(true)?;
+    let stream = unsafe { TcpStream::from_raw_fd(std_stream.into_raw_fd()) };
+
+    result.map(|conn| (stream, conn))
+}
+
+#[cfg(not(unix))]
+pub fn handshake_over_tcp(_stream: TcpStream,
+                           _we_are_initiator: bool,
+                           _deadline: Instant)
+                           -> ::Res<(TcpStream, EncryptedConnection)> {
+    Err(NatError::HandshakeFailed)
+}
+
+fn run_tcp_handshake(stream: &mut StdTcpStream, we_are_initiator: bool) -> ::Res<EncryptedConnection> {
+    let peer_addr = stream.peer_addr()?;
+    let (our_public, our_secret) = box_::gen_keypair();
+    let our_nonce = gen_nonce32();
+
+    let (their_public, their_nonce) = if we_are_initiator {
+        send_message(stream, &AuthMessage { public_key: our_public, nonce: our_nonce })?;
+        let ack: AckMessage = recv_message(stream)?;
+        (ack.public_key, ack.nonce)
+    } else {
+        let auth: AuthMessage = recv_message(stream)?;
+        send_message(stream, &AckMessage { public_key: our_public, nonce: our_nonce })?;
+        (auth.public_key, auth.nonce)
+    };
+
+    EncryptedConnection::new(peer_addr,
+                             &our_secret,
+                             &our_nonce,
+                             &their_public,
+                             &their_nonce,
+                             we_are_initiator)
+}
+
+fn send_message<T: ::serde::Serialize>(stream: &mut StdTcpStream, msg: &T) -> ::Res<()> {
+    let bytes = serialize(msg, Infinite)?;
+    stream.write_all(&bytes).map_err(NatError::from)
+}
+
+fn recv_message<T: ::serde::de::DeserializeOwned>(stream: &mut StdTcpStream) -> ::Res<T> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf)?;
+    deserialize(&buf[..n]).map_err(NatError::from)
+}
+
+fn gen_nonce32() -> [u8; NONCE_LEN] {
+    let mut out = [0u8; NONCE_LEN];
+    randombytes_into(&mut out);
+    out
+}
+
+fn xor32(a: &[u8], b: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    for i in 0..32 {
+        out[i] = a.get(i).cloned().unwrap_or(0) ^ b.get(i).cloned().unwrap_or(0);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_pair() -> (EncryptedConnection, EncryptedConnection) {
+        let (a_public, a_secret) = box_::gen_keypair();
+        let (b_public, b_secret) = box_::gen_keypair();
+        let a_nonce = gen_nonce32();
+        let b_nonce = gen_nonce32();
+
+        let a_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let b_addr: SocketAddr = "127.0.0.1:5678".parse().unwrap();
+
+        let a_conn = EncryptedConnection::new(b_addr, &a_secret, &a_nonce, &b_public, &b_nonce, true)
+            .unwrap();
+        let b_conn = EncryptedConnection::new(a_addr, &b_secret, &b_nonce, &a_public, &a_nonce, false)
+            .unwrap();
+
+        (a_conn, b_conn)
+    }
+
+    #[test]
+    fn gen_nonce32_fills_the_whole_buffer() {
+        // A run of 32 zero bytes is astronomically unlikely from a real RNG;
+        // this is the regression test for the old bug where only the first
+        // 24 bytes were ever populated.
+        let nonce = gen_nonce32();
+        assert!(nonce.iter().any(|&b| b != 0));
+        assert_ne!(&nonce[24..], &[0u8; 8][..]);
+    }
+
+    #[test]
+    fn round_trip_encrypt_decrypt() {
+        let (mut a_conn, mut b_conn) = peer_pair();
+
+        let plain = b"the quick brown fox jumps over the lazy dog".to_vec();
+        let frame = a_conn.encrypt_frame(&plain);
+        let decrypted = b_conn.decrypt_frame(&frame).unwrap();
+
+        assert_eq!(decrypted, plain);
+    }
+
+    #[test]
+    fn seeds_are_symmetric_in_both_directions() {
+        let (mut a_conn, mut b_conn) = peer_pair();
+
+        let a_to_b = a_conn.encrypt_frame(b"hello from a");
+        assert_eq!(b_conn.decrypt_frame(&a_to_b).unwrap(), b"hello from a");
+
+        let b_to_a = b_conn.encrypt_frame(b"hello from b");
+        assert_eq!(a_conn.decrypt_frame(&b_to_a).unwrap(), b"hello from b");
+    }
+
+    #[test]
+    fn tampered_frame_fails_mac_verification() {
+        let (mut a_conn, mut b_conn) = peer_pair();
+
+        let mut frame = a_conn.encrypt_frame(b"untouched payload");
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff;
+
+        match b_conn.decrypt_frame(&frame) {
+            Err(NatError::MacVerificationFailed) => (),
+            other => panic!("expected MacVerificationFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reordered_frame_desyncs_the_running_mac() {
+        // Documents the known limitation that keeps the encrypted layer
+        // TCP-only: once a frame is skipped, the running MAC can never
+        // verify again, even though the skipped frame itself was genuine.
+        let (mut a_conn, mut b_conn) = peer_pair();
+
+        let first = a_conn.encrypt_frame(b"first");
+        let second = a_conn.encrypt_frame(b"second");
+
+        assert!(b_conn.decrypt_frame(&second).is_err());
+        assert!(b_conn.decrypt_frame(&first).is_err());
+    }
+}
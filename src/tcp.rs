@@ -0,0 +1,370 @@
+use {Interface, NatError, NatState, NatTimer};
+use libc;
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::tcp::TcpStream;
+use mio::timer::Timeout;
+use net2::TcpBuilder;
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Formatter};
+use std::mem;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::net::TcpStream as StdTcpStream;
+use std::rc::{Rc, Weak};
+use std::time::Instant;
+
+pub type RendezvousFinsih = Box<FnMut(&mut Interface, &Poll, ::Res<Vec<SocketAddr>>)>;
+pub type HolePunchFinsih = Box<FnMut(&mut Interface, &Poll, ::Res<(TcpStream, Token)>)>;
+
+const TIMER_ID: u8 = 0;
+
+/// Mirrors the simultaneous-open handshake that OpenEthereum's `HandshakeState`
+/// drives for a raw socket: we never know in advance whether our outbound
+/// `connect()` or the peer's inbound SYN will win the race, so the state
+/// machine just tracks how far the socket has got. `New` covers the instant
+/// between deciding to punch and the non-blocking `connect()` actually being
+/// issued and registered with `poll`; `Connecting` is that registered socket
+/// waiting on its first readiness edge; `Connected` is set once that edge has
+/// been checked for a pending error and found clean.
+#[derive(Debug, PartialEq)]
+enum HandshakeState {
+    New,
+    Connecting,
+    Connected,
+}
+
+enum State {
+    None,
+    Rendezvous {
+        builder: TcpBuilder,
+        local_addr: SocketAddr,
+        timeout: Timeout,
+        f: RendezvousFinsih,
+    },
+    ReadyToHolePunch {
+        builder: TcpBuilder,
+        local_addr: SocketAddr,
+    },
+    HolePunching {
+        handshake: HandshakeState,
+        sock: Option<TcpStream>,
+        token: Token,
+        timeout: Timeout,
+        f: HolePunchFinsih,
+    },
+}
+impl Debug for State {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            State::None => write!(f, "State::None"),
+            State::Rendezvous { .. } => write!(f, "State::Rendezvous"),
+            State::ReadyToHolePunch { .. } => write!(f, "State::ReadyToHolePunch"),
+            State::HolePunching { .. } => write!(f, "State::HolePunching"),
+        }
+    }
+}
+
+/// Drives TCP rendezvous and simultaneous-open hole punching for a single
+/// peer. The socket bound in `start` (with `SO_REUSEADDR`/`SO_REUSEPORT`) is
+/// kept alive into `punch_hole`, so the punch goes out from the same local
+/// port that was reported at rendezvous. No rendezvous server is contacted
+/// over TCP: the address reported by `rendezvous_timeout` is that socket's
+/// local bound address (`0.0.0.0:<port>`), not an externally observed one.
+pub struct TcpHolePunchMediator {
+    token: Token,
+    state: State,
+    self_weak: Weak<RefCell<TcpHolePunchMediator>>,
+}
+
+impl TcpHolePunchMediator {
+    pub fn start(ifc: &mut Interface,
+                  poll: &Poll,
+                  deadline: Instant,
+                  f: RendezvousFinsih)
+                  -> ::Res<Rc<RefCell<TcpHolePunchMediator>>> {
+        let token = ifc.new_token();
+        let timeout = ifc.set_timeout(::hole_punch::duration_until(deadline), NatTimer::new(token, TIMER_ID))?;
+
+        // Bind an ephemeral local port with SO_REUSEADDR/SO_REUSEPORT and keep
+        // this exact bound socket (not just its port number) alive all the
+        // way into `punch_hole`, so the simultaneous-open connect goes out
+        // from the same local port that `rendezvous_timeout` reports.
+        let builder = TcpBuilder::new_v4()?;
+        let _ = builder.reuse_address(true)?;
+        let _ = reuse_port(&builder)?;
+        let _ = builder.bind("0.0.0.0:0")?;
+        let local_addr = builder.local_addr()?;
+
+        let mediator = Rc::new(RefCell::new(TcpHolePunchMediator {
+            token: token,
+            state: State::Rendezvous {
+                builder: builder,
+                local_addr: local_addr,
+                timeout: timeout,
+                f: f,
+            },
+            self_weak: Weak::new(),
+        }));
+        let weak = Rc::downgrade(&mediator);
+        mediator.borrow_mut().self_weak = weak;
+
+        if let Err((nat_state, e)) = ifc.insert_state(token, mediator.clone()) {
+            error!("To be handled properly: {}", e);
+            nat_state.borrow_mut().terminate(ifc, poll);
+            return Err(NatError::HolePunchMediatorFailedToStart);
+        }
+
+        Ok(mediator)
+    }
+
+    pub fn rendezvous_timeout(&mut self, _ifc: &mut Interface, _poll: &Poll) -> ::Res<Vec<SocketAddr>> {
+        match self.state {
+            State::Rendezvous { local_addr, .. } => Ok(vec![local_addr]),
+            ref x => {
+                warn!("Logic Error in state book-keeping - Pls report this as a bug. Expected \
+                       state: State::Rendezvous ;; Found: {:?}",
+                      x);
+                Err(NatError::InvalidState)
+            }
+        }
+    }
+
+    pub fn punch_hole(&mut self,
+                       ifc: &mut Interface,
+                       poll: &Poll,
+                       deadline: Instant,
+                       peers: Vec<SocketAddr>,
+                       mut f: HolePunchFinsih)
+                       -> ::Res<()> {
+        let builder = match mem::replace(&mut self.state, State::None) {
+            State::ReadyToHolePunch { builder, .. } => builder,
+            x => {
+                debug!("Improper state for this operation: {:?}", x);
+                self.state = x;
+                return Err(NatError::HolePunchFailed);
+            }
+        };
+
+        let peer = match peers.into_iter().next() {
+            Some(p) => p,
+            None => return Err(NatError::HolePunchFailed),
+        };
+
+        let timeout = ifc.set_timeout(::hole_punch::duration_until(deadline), NatTimer::new(self.token, TIMER_ID))?;
+        let sock_token = ifc.new_token();
+
+        // Not yet connecting - the state records that the connect has not
+        // been issued yet, rather than jumping straight to `Connecting`. If
+        // issuing or registering the connect fails below, the state is reset
+        // to `State::None` before returning the error.
+        self.state = State::HolePunching {
+            handshake: HandshakeState::New,
+            sock: None,
+            token: sock_token,
+            timeout: timeout,
+            f: f,
+        };
+
+        // Reuse the very socket that was bound (and whose port was handed to
+        // the peer) during rendezvous: both sides `connect()` to each other
+        // from that same 4-tuple at once, so the kernel completes the
+        // handshake as a simultaneous open with no `listen()`/`accept()` on
+        // either side. The connect is issued non-blocking so it never stalls
+        // the event-loop thread; `handle_socket_ready` observes completion
+        // once `poll` reports the socket writable.
+        let sock = match connect_nonblocking(builder, peer) {
+            Ok(sock) => sock,
+            Err(e) => {
+                self.state = State::None;
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = poll.register(&sock,
+                                      sock_token,
+                                      Ready::writable() | Ready::readable(),
+                                      PollOpt::edge() | PollOpt::oneshot()) {
+            self.state = State::None;
+            return Err(NatError::from(e));
+        }
+
+        if let State::HolePunching { ref mut handshake, sock: ref mut sock_slot, .. } = self.state {
+            *handshake = HandshakeState::Connecting;
+            *sock_slot = Some(sock);
+        }
+
+        if let Some(rc) = self.self_weak.upgrade() {
+            if let Err((nat_state, e)) = ifc.insert_state(sock_token, rc) {
+                debug!("Failed to register hole-punch socket token, terminating: {:?}", e);
+                nat_state.borrow_mut().terminate(ifc, poll);
+                return Err(NatError::HolePunchFailed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Called when the registered socket becomes writable or readable. In a
+    /// true simultaneous open there is no `listen()`/`accept()` on either
+    /// side: both peers `connect()` from the same bound port at once and the
+    /// kernel completes the handshake directly on that connecting socket, so
+    /// whichever readiness edge fires first just means the connection is
+    /// now established and it is safe to check for a pending connect error.
+    pub fn handle_socket_ready(&mut self, ifc: &mut Interface, poll: &Poll) {
+        let r = match self.state {
+            State::HolePunching { ref mut handshake, ref mut sock, token, ref mut f, .. } => {
+                if *handshake == HandshakeState::Connected {
+                    return;
+                }
+                let established = sock.as_ref()
+                    .map(|s| s.take_error().ok().and_then(|e| e).is_none())
+                    .unwrap_or(false);
+                if established {
+                    *handshake = HandshakeState::Connected;
+                    let sock = sock.take().expect("sock cannot be None here");
+                    f(ifc, poll, Ok((sock, token)));
+                    Ok(true)
+                } else {
+                    f(ifc, poll, Err(NatError::HolePunchFailed));
+                    Err(NatError::HolePunchFailed)
+                }
+            }
+            ref x => {
+                warn!("Logic Error in state book-keeping - Pls report this as a bug. Expected \
+                       state: State::HolePunching ;; Found: {:?}",
+                      x);
+                Err(NatError::InvalidState)
+            }
+        };
+
+        if let Err(_) = r {
+            self.terminate(ifc, poll);
+        }
+    }
+}
+
+impl NatState for TcpHolePunchMediator {
+    fn timeout(&mut self, ifc: &mut Interface, poll: &Poll, timer_id: u8) {
+        if timer_id != TIMER_ID {
+            debug!("Invalid Timer ID: {}", timer_id);
+            return;
+        }
+
+        let ready = match self.state {
+            State::Rendezvous { .. } => true,
+            _ => false,
+        };
+        if ready {
+            if let State::Rendezvous { builder, local_addr, .. } =
+                mem::replace(&mut self.state, State::None) {
+                self.state = State::ReadyToHolePunch {
+                    builder: builder,
+                    local_addr: local_addr,
+                };
+            }
+            return;
+        }
+
+        match self.state {
+            State::HolePunching { ref mut f, .. } => {
+                f(ifc, poll, Err(NatError::HolePunchFailed));
+                self.terminate(ifc, poll);
+            }
+            ref x => {
+                warn!("Logic error, report bug: terminating due to invalid state for a timeout: \
+                       {:?}",
+                      x);
+            }
+        }
+    }
+
+    fn terminate(&mut self, ifc: &mut Interface, poll: &Poll) {
+        let _ = ifc.remove_state(self.token);
+        match self.state {
+            State::Rendezvous { ref timeout, .. } |
+            State::HolePunching { ref timeout, .. } => {
+                let _ = ifc.cancel_timeout(timeout);
+            }
+            _ => (),
+        }
+        if let State::HolePunching { ref sock, token, .. } = self.state {
+            if let Some(ref sock) = *sock {
+                let _ = poll.deregister(sock);
+            }
+            let _ = ifc.remove_state(token);
+        }
+    }
+
+    fn ready(&mut self, ifc: &mut Interface, poll: &Poll, _event: Ready) {
+        self.handle_socket_ready(ifc, poll);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(unix)]
+fn reuse_port(builder: &TcpBuilder) -> ::Res<()> {
+    use net2::unix::UnixTcpBuilderExt;
+    builder.reuse_port(true)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reuse_port(_builder: &TcpBuilder) -> ::Res<()> {
+    Ok(())
+}
+
+/// Issues a non-blocking `connect()` on `builder`'s already-bound socket,
+/// reusing that exact fd (and so the exact bound 4-tuple) rather than
+/// letting a higher-level API open a brand new one. `net2::TcpBuilder::connect`
+/// can't be used for this: it performs a real, blocking connect and, on the
+/// `EINPROGRESS` a non-blocking connect is expected to return, drops the
+/// socket along with the error - so the raw syscalls are done by hand here,
+/// exactly as `net2`/`mio` would do internally for a plain `connect()`.
+#[cfg(unix)]
+fn connect_nonblocking(builder: TcpBuilder, peer: SocketAddr) -> ::Res<TcpStream> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    let peer_v4 = match peer {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => return Err(NatError::HolePunchFailed),
+    };
+
+    let fd = builder.into_raw_fd();
+
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        let e = ::std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(NatError::from(e));
+    }
+
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_port = peer_v4.port().to_be();
+    sin.sin_addr.s_addr = u32::from(*peer_v4.ip()).to_be();
+
+    let ret = unsafe {
+        libc::connect(fd,
+                      &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+                      mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+    };
+    if ret < 0 {
+        let e = ::std::io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            unsafe { libc::close(fd) };
+            return Err(NatError::from(e));
+        }
+    }
+
+    let std_stream = unsafe { StdTcpStream::from_raw_fd(fd) };
+    TcpStream::connect_stream(std_stream, &peer).map_err(NatError::from)
+}
+
+#[cfg(not(unix))]
+fn connect_nonblocking(builder: TcpBuilder, peer: SocketAddr) -> ::Res<TcpStream> {
+    TcpStream::connect_stream(builder.connect(&peer)?, &peer).map_err(NatError::from)
+}
@@ -0,0 +1,102 @@
+use {Interface, NatError, NatState, NatTimer};
+use config::{RATE_LIMITER_CAPACITY, RATE_LIMITER_GC_SEC, RATE_LIMITER_REFILL_PER_SEC};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::udp::UdpSocket;
+use ratelimit::RateLimiter;
+use std::any::Any;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const GC_TIMER_ID: u8 = 0;
+
+/// Answers UDP rendezvous requests with the public `SocketAddr` the request
+/// was observed from. Every inbound datagram is first checked against a
+/// per-source-IP token bucket (see `ratelimit::RateLimiter`) so that a single
+/// peer - or a forged source address used for reflection/amplification -
+/// cannot make the server do unbounded work.
+pub struct UdpRendezvousServer {
+    token: Token,
+    sock: UdpSocket,
+    limiter: RateLimiter,
+}
+
+impl UdpRendezvousServer {
+    pub fn start(ifc: &mut Interface, poll: &Poll, sock: UdpSocket) -> ::Res<Token> {
+        let token = ifc.new_token();
+        poll.register(&sock, token, Ready::readable(), PollOpt::edge())?;
+
+        let capacity = ifc.config().rate_limiter_capacity.unwrap_or(RATE_LIMITER_CAPACITY);
+        let refill_rate = ifc.config().rate_limiter_refill_per_sec.unwrap_or(RATE_LIMITER_REFILL_PER_SEC);
+        let gc_dur = ifc.config().rate_limiter_gc_sec.unwrap_or(RATE_LIMITER_GC_SEC);
+        let _ = ifc.set_timeout(Duration::from_secs(gc_dur), NatTimer::new(token, GC_TIMER_ID))?;
+
+        let server = UdpRendezvousServer {
+            token: token,
+            sock: sock,
+            limiter: RateLimiter::new(capacity, refill_rate),
+        };
+
+        if let Err((nat_state, e)) = ifc.insert_state(token, ::std::rc::Rc::new(::std::cell::RefCell::new(server))) {
+            error!("To be handled properly: {}", e);
+            nat_state.borrow_mut().terminate(ifc, poll);
+            return Err(NatError::UdpRendezvousServerStartFailed);
+        }
+
+        Ok(token)
+    }
+
+    fn readable(&mut self, _ifc: &mut Interface, _poll: &Poll) {
+        let mut buf = [0u8; 256];
+        loop {
+            let (bytes_read, peer) = match self.sock.recv_from(&mut buf) {
+                Ok(Some(res)) => res,
+                Ok(None) => return,
+                Err(e) => {
+                    debug!("Error reading from rendezvous socket: {:?}", e);
+                    return;
+                }
+            };
+
+            if !self.limiter.allow(peer.ip(), Instant::now()) {
+                trace!("Dropping rendezvous request from {}: {:?}", peer, NatError::RateLimited);
+                continue;
+            }
+
+            if let Err(e) = self.answer(peer) {
+                debug!("Error answering rendezvous request from {}: {:?}", peer, e);
+            }
+            let _ = bytes_read;
+        }
+    }
+
+    fn answer(&mut self, peer: SocketAddr) -> ::Res<()> {
+        let payload = ::bincode::serialize(&peer, ::bincode::Infinite)?;
+        let _ = self.sock.send_to(&payload, &peer)?;
+        Ok(())
+    }
+}
+
+impl NatState for UdpRendezvousServer {
+    fn ready(&mut self, ifc: &mut Interface, poll: &Poll, _event: Ready) {
+        self.readable(ifc, poll);
+    }
+
+    fn timeout(&mut self, ifc: &mut Interface, poll: &Poll, timer_id: u8) {
+        if timer_id != GC_TIMER_ID {
+            debug!("Invalid Timer ID: {}", timer_id);
+            return;
+        }
+        let gc_dur = ifc.config().rate_limiter_gc_sec.unwrap_or(RATE_LIMITER_GC_SEC);
+        self.limiter.gc(Instant::now(), Duration::from_secs(gc_dur));
+        let _ = ifc.set_timeout(Duration::from_secs(gc_dur), NatTimer::new(self.token, GC_TIMER_ID));
+    }
+
+    fn terminate(&mut self, ifc: &mut Interface, poll: &Poll) {
+        let _ = ifc.remove_state(self.token);
+        let _ = poll.deregister(&self.sock);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
@@ -0,0 +1,111 @@
+use {Handle, Interface, NatMsg, RendezvousInfo};
+use hole_punch::HolePunchMediator;
+use mio::Poll;
+use mio::Token;
+use mio::channel::{self, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// Like `GetInfo`, but `Send`. `start_hole_punch_mediator` hands the
+/// callback off to a worker thread's own event loop rather than running it
+/// on the caller's thread, so - unlike the plain `GetInfo` a caller already
+/// on an event loop thread uses with `HolePunchMediator::start` directly -
+/// it has to actually be safe to move across that boundary.
+pub type SendGetInfo = Box<FnMut(&mut Interface, &Poll, ::Res<(Handle, RendezvousInfo)>) + Send>;
+
+/// One IO thread, each owning its own `mio::Poll` and `Token` space (handed
+/// out by that thread's own `Interface`). Mediator state (`Rc`/`RefCell`)
+/// stays thread-local exactly as it is today - a `HolePunchMediator` is
+/// only ever touched from the worker it was created on - so nothing in
+/// `hole_punch.rs`/`tcp.rs`/`udp.rs` needs to become `Arc`/`Mutex`. What
+/// changes is that there are now several such event loops running
+/// concurrently instead of one.
+pub struct Worker {
+    tx: Sender<NatMsg>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    /// `index` and `num_workers` seed the thread's `Interface` so that the
+    /// `Token`s it hands out are actually strided (`index, index +
+    /// num_workers, index + 2 * num_workers, ..`) rather than just being
+    /// documented as such - that stride is what lets `Dispatcher::route`
+    /// recover the owning worker from a bare `Token`.
+    fn spawn(index: usize, num_workers: usize) -> ::std::io::Result<Worker> {
+        let (tx, rx) = channel::channel();
+        let join_handle = thread::Builder::new()
+            .name("p2p-io-worker".into())
+            .spawn(move || ::run_event_loop(rx, index, num_workers))?;
+
+        Ok(Worker {
+            tx: tx,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn send(&self, msg: NatMsg) {
+        if let Err(e) = self.tx.send(msg) {
+            debug!("Failed to route message to worker: {:?}", e);
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+    }
+}
+
+/// Assigns mediators to workers and routes `NatMsg`s to whichever worker
+/// owns the `Token` a message is destined for, so that `Handle` (and
+/// anything else addressing state by `Token`) keeps working unmodified
+/// across a multi-threaded pool.
+pub struct Dispatcher {
+    workers: Vec<Worker>,
+    next: AtomicUsize,
+}
+
+impl Dispatcher {
+    pub fn new(num_workers: usize) -> ::std::io::Result<Dispatcher> {
+        let mut workers = Vec::with_capacity(num_workers);
+        for index in 0..num_workers {
+            workers.push(Worker::spawn(index, num_workers)?);
+        }
+        Ok(Dispatcher {
+            workers: workers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Picks a worker for a brand new mediator, round-robin, and returns
+    /// its index so that later messages for the `Token`s it hands out can
+    /// be routed back to the same worker via `route`.
+    pub fn assign(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len()
+    }
+
+    /// Routes a message to the worker that owns `token`'s space. Workers
+    /// partition the `Token` space by construction (worker `i` only ever
+    /// hands out tokens `i, i + N, i + 2N, ..`), so the owning worker can be
+    /// recovered from the token alone without a separate lookup table.
+    pub fn route(&self, token: Token, msg: NatMsg) {
+        let idx = token.0 % self.workers.len();
+        self.workers[idx].send(msg);
+    }
+
+    /// Entry point for callers that are not already running on one of the
+    /// pool's threads: picks a worker round-robin and has it run
+    /// `HolePunchMediator::start` against its own `Interface`/`Poll`, so
+    /// concurrent callers fan out across the pool instead of piling onto a
+    /// single thread's event loop.
+    pub fn start_hole_punch_mediator(&self, deadline: ::std::time::Instant, f: SendGetInfo) {
+        let idx = self.assign();
+        self.workers[idx].send(NatMsg::new(move |ifc, poll| {
+            if let Err(e) = HolePunchMediator::start(ifc, poll, deadline, f) {
+                debug!("Worker failed to start hole punch mediator: {:?}", e);
+            }
+        }));
+    }
+}